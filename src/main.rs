@@ -2,15 +2,22 @@ use anyhow::{bail, Result};
 use clap::Parser;
 use dgraph_tonic::{Client, Mutate, Mutation, DgraphError, ClientError};
 use futures::prelude::*;
+use futures::stream;
 use indicatif::{ProgressBar, ProgressStyle, ProgressDrawTarget};
 use log::*;
 use rand::Rng;
 use regex::Regex;
+use serde::Serialize;
 use serde_json::{json, Value};
-use std::sync::atomic::{Ordering::AcqRel, AtomicU64};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{Ordering, Ordering::AcqRel, AtomicU64};
+use std::time::{Duration, Instant};
 use std::mem::replace;
-use tokio::io::{stdin, BufReader, AsyncBufReadExt};
+use tokio::io::{stdin, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, AsyncBufReadExt};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex as AsyncMutex;
 use tokio_stream::wrappers::LinesStream;
 use tonic::Code;
 
@@ -23,15 +30,408 @@ struct Args {
     /// more than once)
     #[clap(short = 'U', long = "upsert-pattern")]
     upsert_patterns: Vec<String>,
-    /// Number of documents to load in each transaction
+    /// Number of documents to load in each transaction. Mutually exclusive with
+    /// --nquad-budget; if neither is given, --nquad-budget is derived from the input size
+    /// when stdin is a seekable file
     #[clap(short = 's', long)]
-    chunk_size: usize,
+    chunk_size: Option<usize>,
+    /// Target number of anticipated n-quads to accumulate per transaction, instead of a
+    /// fixed document count. Chunks are sealed as soon as the running total for the
+    /// documents gathered so far reaches this budget, so transaction size stays roughly
+    /// even when document sizes vary wildly
+    #[clap(short = 'n', long)]
+    nquad_budget: Option<u64>,
     /// How many transactions to run at once
     #[clap(short = 'c', long)]
     concurrency: usize,
     /// Disable progress output
     #[clap(short = 'q', long)]
     quiet: bool,
+    /// What to do with a document that fails to parse, uses an unsupported uid, or whose
+    /// mutation is ultimately rejected: `fail` (the default) aborts the whole load, `skip`
+    /// sets the document aside (see --error-output) and keeps going
+    #[clap(long, default_value = "fail")]
+    on_error: OnError,
+    /// JSONL file to write one record to per document set aside by --on-error=skip; an
+    /// existing file is truncated at startup
+    #[clap(long)]
+    error_output: Option<PathBuf>,
+    /// Maximum number of times to retry a transaction after a transient error before
+    /// giving up and treating the whole chunk as rejected (see --on-error)
+    #[clap(long, default_value = "8")]
+    max_retries: u32,
+    /// Base delay in milliseconds for exponential backoff between retries
+    #[clap(long, default_value = "500")]
+    retry_base_ms: u64,
+    /// Upper bound in milliseconds for the backoff delay between retries
+    #[clap(long, default_value = "30000")]
+    retry_cap_ms: u64,
+    /// Address to serve a Prometheus /metrics endpoint on (e.g. 0.0.0.0:9100), for
+    /// headless/--quiet runs where the indicatif spinner can't be watched
+    #[clap(long)]
+    metrics_listen: Option<std::net::SocketAddr>,
+}
+
+/// How to react to a single bad document or rejected mutation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OnError {
+    Skip,
+    Fail,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnError::Skip),
+            "fail" => Ok(OnError::Fail),
+            other => Err(format!("invalid --on-error value {:?} (expected \"skip\" or \"fail\")", other)),
+        }
+    }
+}
+
+/// Why a document was set aside instead of loaded
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FailureReason {
+    ParseError,
+    UnsupportedUid,
+    InvalidOperation,
+    MutationRejected,
+}
+
+/// One line of the --error-output JSONL dead-letter file
+#[derive(Serialize)]
+struct FailureRecord<'a> {
+    index: usize,
+    line: &'a str,
+    reason: FailureReason,
+    message: String,
+}
+
+/// Classifies and, when configured, persists documents set aside by --on-error=skip
+struct ErrorSink {
+    on_error: OnError,
+    file: AsyncMutex<Option<BufWriter<tokio::fs::File>>>,
+}
+
+impl ErrorSink {
+    fn new(on_error: OnError, file: Option<tokio::fs::File>) -> Self {
+        ErrorSink {
+            on_error,
+            file: AsyncMutex::new(file.map(BufWriter::new)),
+        }
+    }
+
+    /// Report a bad document: fatal under `--on-error=fail`, otherwise logged and, if
+    /// --error-output is set, appended to the dead-letter file
+    async fn handle(&self, index: usize, line: &str, reason: FailureReason, message: String) -> Result<()> {
+        if self.on_error == OnError::Fail {
+            bail!("{:?} at line {}: {}", reason, index, message);
+        }
+
+        warn!("Skipping line {} ({:?}): {}", index, reason, message);
+
+        let mut guard = self.file.lock().await;
+
+        if let Some(file) = guard.as_mut() {
+            let record = FailureRecord { index, line, reason, message };
+            file.write_all(serde_json::to_string(&record)?.as_bytes()).await?;
+            file.write_all(b"\n").await?;
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounded exponential backoff with full jitter for retrying transient transaction failures
+#[derive(Clone, Copy)]
+struct RetryPolicy {
+    max_retries: u32,
+    base_ms: u64,
+    cap_ms: u64,
+}
+
+impl RetryPolicy {
+    /// On attempt `n`, waits a random duration in `[0, min(cap, base * 2^n)]`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = 2u64.checked_pow(attempt).unwrap_or(u64::MAX);
+        let max_delay = self.base_ms.saturating_mul(exp).min(self.cap_ms);
+        let msec = rand::thread_rng().gen_range(0..=max_delay);
+
+        Duration::from_millis(msec)
+    }
+}
+
+/// Per-chunk count of retried transaction attempts, broken out by the status code that
+/// triggered the retry
+#[derive(Default)]
+struct AbortCounts {
+    aborted: u64,
+    resource_exhausted: u64,
+    unavailable: u64,
+    deadline_exceeded: u64,
+}
+
+impl AbortCounts {
+    fn total(&self) -> u64 {
+        self.aborted + self.resource_exhausted + self.unavailable + self.deadline_exceeded
+    }
+}
+
+/// Running totals of `AbortCounts` across every chunk processed so far
+struct AbortCounters {
+    aborted: AtomicU64,
+    resource_exhausted: AtomicU64,
+    unavailable: AtomicU64,
+    deadline_exceeded: AtomicU64,
+}
+
+impl AbortCounters {
+    fn new() -> Self {
+        AbortCounters {
+            aborted: AtomicU64::new(0),
+            resource_exhausted: AtomicU64::new(0),
+            unavailable: AtomicU64::new(0),
+            deadline_exceeded: AtomicU64::new(0),
+        }
+    }
+
+    /// Folds a chunk's counts into the running totals, returning the new totals
+    fn add(&self, counts: &AbortCounts) -> AbortCounts {
+        AbortCounts {
+            aborted: self.aborted.fetch_add(counts.aborted, AcqRel) + counts.aborted,
+            resource_exhausted:
+                self.resource_exhausted.fetch_add(counts.resource_exhausted, AcqRel) + counts.resource_exhausted,
+            unavailable: self.unavailable.fetch_add(counts.unavailable, AcqRel) + counts.unavailable,
+            deadline_exceeded:
+                self.deadline_exceeded.fetch_add(counts.deadline_exceeded, AcqRel) + counts.deadline_exceeded,
+        }
+    }
+}
+
+/// Upper bounds (seconds) of the upsert/commit latency histogram buckets, terminated by +Inf
+static LATENCY_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, f64::INFINITY];
+
+/// A cumulative Prometheus-style histogram of `upsert_and_commit_now` round-trip latencies
+struct LatencyHistogram {
+    bucket_counts: Vec<AtomicU64>,
+    sum_millis: AtomicU64,
+    count: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn new() -> Self {
+        LatencyHistogram {
+            bucket_counts: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_millis: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let index = LATENCY_BUCKETS.iter().position(|&upper| secs <= upper)
+            .unwrap_or(LATENCY_BUCKETS.len() - 1);
+
+        self.bucket_counts[index].fetch_add(1, AcqRel);
+        self.sum_millis.fetch_add(elapsed.as_millis() as u64, AcqRel);
+        self.count.fetch_add(1, AcqRel);
+    }
+
+    fn render(&self, out: &mut String) {
+        use std::fmt::Write;
+
+        let mut cumulative = 0u64;
+
+        for (upper, counter) in LATENCY_BUCKETS.iter().zip(&self.bucket_counts) {
+            cumulative += counter.load(Ordering::Acquire);
+
+            let le = if upper.is_infinite() { "+Inf".to_string() } else { upper.to_string() };
+
+            writeln!(out, "dgraph_loader_upsert_commit_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative).ok();
+        }
+
+        writeln!(out, "dgraph_loader_upsert_commit_duration_seconds_sum {}",
+            self.sum_millis.load(Ordering::Acquire) as f64 / 1000.0).ok();
+        writeln!(out, "dgraph_loader_upsert_commit_duration_seconds_count {}",
+            self.count.load(Ordering::Acquire)).ok();
+    }
+}
+
+/// Shared counters behind both the indicatif spinner and the optional /metrics endpoint
+struct Metrics {
+    txns: AtomicU64,
+    docs: AtomicU64,
+    nquads: AtomicU64,
+    failed: AtomicU64,
+    aborts: AbortCounters,
+    commit_latency: LatencyHistogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            txns: AtomicU64::new(0),
+            docs: AtomicU64::new(0),
+            nquads: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            aborts: AbortCounters::new(),
+            commit_latency: LatencyHistogram::new(),
+        }
+    }
+
+    /// Renders every metric in Prometheus text exposition format
+    fn render(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+
+        writeln!(out, "# HELP dgraph_loader_committed_transactions_total Transactions committed successfully").ok();
+        writeln!(out, "# TYPE dgraph_loader_committed_transactions_total counter").ok();
+        writeln!(out, "dgraph_loader_committed_transactions_total {}", self.txns.load(Ordering::Acquire)).ok();
+
+        writeln!(out, "# HELP dgraph_loader_documents_loaded_total Documents successfully loaded").ok();
+        writeln!(out, "# TYPE dgraph_loader_documents_loaded_total counter").ok();
+        writeln!(out, "dgraph_loader_documents_loaded_total {}", self.docs.load(Ordering::Acquire)).ok();
+
+        writeln!(out, "# HELP dgraph_loader_nquads_loaded_total Anticipated n-quads successfully loaded").ok();
+        writeln!(out, "# TYPE dgraph_loader_nquads_loaded_total counter").ok();
+        writeln!(out, "dgraph_loader_nquads_loaded_total {}", self.nquads.load(Ordering::Acquire)).ok();
+
+        writeln!(out, "# HELP dgraph_loader_documents_failed_total Documents set aside by --on-error=skip").ok();
+        writeln!(out, "# TYPE dgraph_loader_documents_failed_total counter").ok();
+        writeln!(out, "dgraph_loader_documents_failed_total {}", self.failed.load(Ordering::Acquire)).ok();
+
+        writeln!(out, "# HELP dgraph_loader_retries_total Retried transaction attempts, by status code").ok();
+        writeln!(out, "# TYPE dgraph_loader_retries_total counter").ok();
+        writeln!(out, "dgraph_loader_retries_total{{code=\"aborted\"}} {}",
+            self.aborts.aborted.load(Ordering::Acquire)).ok();
+        writeln!(out, "dgraph_loader_retries_total{{code=\"resource_exhausted\"}} {}",
+            self.aborts.resource_exhausted.load(Ordering::Acquire)).ok();
+        writeln!(out, "dgraph_loader_retries_total{{code=\"unavailable\"}} {}",
+            self.aborts.unavailable.load(Ordering::Acquire)).ok();
+        writeln!(out, "dgraph_loader_retries_total{{code=\"deadline_exceeded\"}} {}",
+            self.aborts.deadline_exceeded.load(Ordering::Acquire)).ok();
+
+        writeln!(out, "# HELP dgraph_loader_upsert_commit_duration_seconds Latency of upsert_and_commit_now round-trips").ok();
+        writeln!(out, "# TYPE dgraph_loader_upsert_commit_duration_seconds histogram").ok();
+        self.commit_latency.render(&mut out);
+
+        out
+    }
+}
+
+/// Serves the Prometheus text exposition format at any path, forever, until the process exits
+async fn serve_metrics(addr: std::net::SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            // Discard the request; we serve the same body regardless of method or path
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body);
+
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// A line of input, already parsed and nquad-counted (or the reason parsing failed), kept
+/// alongside its stdin index and raw text for error reporting and chunk sealing
+type LineItem = (usize, String, std::result::Result<(Value, u64), String>);
+
+/// How transactions are grouped: a fixed document count, or a running n-quad budget
+enum ChunkingMode {
+    FixedSize(usize),
+    NquadBudget(u64),
+}
+
+/// Rough estimate of JSON bytes per anticipated n-quad, used to translate a raw file size
+/// into an nquad budget when auto-deriving --nquad-budget. Document text (keys, punctuation,
+/// string quoting) means bytes consistently outnumber nquads by roughly this much; it's a
+/// coarse heuristic, not a measurement of the actual input
+const ESTIMATED_BYTES_PER_NQUAD: u64 = 40;
+
+impl ChunkingMode {
+    /// Resolves --chunk-size/--nquad-budget, falling back to a budget derived from the
+    /// input size (converted from bytes to an estimated nquad count, then divided across
+    /// --concurrency workers) when stdin is a seekable file
+    fn resolve(chunk_size: Option<usize>, nquad_budget: Option<u64>, concurrency: usize) -> Result<Self> {
+        if concurrency == 0 {
+            bail!("--concurrency must be at least 1");
+        }
+
+        match (chunk_size, nquad_budget) {
+            (Some(_), Some(_)) => bail!("--chunk-size and --nquad-budget are mutually exclusive"),
+            (Some(n), None) => Ok(ChunkingMode::FixedSize(n)),
+            (None, Some(budget)) => Ok(ChunkingMode::NquadBudget(budget)),
+            (None, None) => match stdin_file_size() {
+                Some(size) => {
+                    let estimated_nquads = size / ESTIMATED_BYTES_PER_NQUAD;
+                    Ok(ChunkingMode::NquadBudget((estimated_nquads / concurrency as u64).max(1)))
+                },
+                None => bail!("one of --chunk-size or --nquad-budget is required when stdin isn't a seekable file"),
+            }
+        }
+    }
+}
+
+/// Size of the file stdin has been redirected from, if it's a seekable regular file rather
+/// than a pipe, used as a rough proxy for total anticipated n-quads when auto-deriving
+/// --nquad-budget
+fn stdin_file_size() -> Option<u64> {
+    use std::io::Seek;
+
+    std::fs::File::open("/dev/stdin").ok()?.seek(std::io::SeekFrom::End(0)).ok()
+}
+
+/// Accumulates lines into chunks once their anticipated n-quads cross `budget`, the way
+/// `try_chunks` accumulates by document count
+fn nquad_budget_chunks<S>(items: S, budget: u64) -> impl Stream<Item = Result<Vec<LineItem>>>
+where
+    S: Stream<Item = Result<LineItem>> + Unpin,
+{
+    stream::unfold(items, move |mut items| async move {
+        let mut chunk = Vec::new();
+        let mut acc: u64 = 0;
+
+        loop {
+            match items.next().await {
+                Some(Ok(item)) => {
+                    if let Ok((_, nquads)) = &item.2 {
+                        acc += nquads;
+                    }
+
+                    chunk.push(item);
+
+                    if acc >= budget {
+                        return Some((Ok(chunk), items));
+                    }
+                },
+                Some(Err(e)) => return Some((Err(e), items)),
+                None => {
+                    if chunk.is_empty() {
+                        return None;
+                    }
+
+                    return Some((Ok(chunk), items));
+                }
+            }
+        }
+    })
 }
 
 #[tokio::main]
@@ -46,6 +446,32 @@ async fn main() -> Result<()> {
 
     let client = Client::new(&args.alpha)?;
 
+    let error_file = match &args.error_output {
+        Some(path) => Some(tokio::fs::File::create(path).await?),
+        None => None,
+    };
+    let error_sink = ErrorSink::new(args.on_error, error_file);
+
+    let chunking_mode = ChunkingMode::resolve(args.chunk_size, args.nquad_budget, args.concurrency)?;
+
+    let retry_policy = RetryPolicy {
+        max_retries: args.max_retries,
+        base_ms: args.retry_base_ms,
+        cap_ms: args.retry_cap_ms,
+    };
+
+    let metrics = Arc::new(Metrics::new());
+
+    if let Some(addr) = args.metrics_listen {
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(addr, metrics).await {
+                error!("Metrics server error: {:?}", e);
+            }
+        });
+    }
+
     let bar = ProgressBar::new_spinner();
 
     if args.quiet {
@@ -57,31 +483,39 @@ async fn main() -> Result<()> {
     bar.set_style(ProgressStyle::default_spinner()
         .template("{spinner} Elapsed:{elapsed_precise} N-Quads:{pos} Rate:{per_sec} {msg}"));
 
-    let txns_counter = AtomicU64::new(0);
-    let docs_counter = AtomicU64::new(0);
-    let abort_counter = AtomicU64::new(0);
-
-    LinesStream::new(BufReader::new(stdin()).lines())
+    let line_items = LinesStream::new(BufReader::new(stdin()).lines())
         .enumerate()
-        .map(|(index, result)| result.map(|v| (index, v)))
-        .try_chunks(args.chunk_size)
-        .map_err(|e| e.into())
-        .map_ok(|chunk| process_chunk(&client, &upsert_patterns, chunk))
+        .map(|(index, result)| -> Result<LineItem> {
+            let line = result?;
+            let parsed = serde_json::from_str::<Value>(&line)
+                .map(|doc| { let nquads = count_nquads(&doc); (doc, nquads) })
+                .map_err(|e| e.to_string());
+            Ok((index, line, parsed))
+        });
+
+    let chunks: std::pin::Pin<Box<dyn Stream<Item = Result<Vec<LineItem>>>>> = match chunking_mode {
+        ChunkingMode::FixedSize(n) => Box::pin(line_items.try_chunks(n).map_err(|e| e.into())),
+        ChunkingMode::NquadBudget(budget) => Box::pin(nquad_budget_chunks(line_items, budget)),
+    };
+
+    chunks
+        .map_ok(|chunk| process_chunk(&client, &upsert_patterns, &error_sink, retry_policy, &metrics, chunk))
         .try_buffer_unordered(args.concurrency)
         .try_for_each(|stats| {
             let bar = &bar;
-            let txns_counter = &txns_counter;
-            let docs_counter = &docs_counter;
-            let abort_counter = &abort_counter;
+            let metrics = &metrics;
 
             async move {
-                let done_txns = txns_counter.fetch_add(1, AcqRel);
-                let done_docs = docs_counter.fetch_add(stats.completed_docs, AcqRel);
-                let aborts = abort_counter.fetch_add(stats.aborted, AcqRel);
+                let done_txns = metrics.txns.fetch_add(if stats.committed { 1 } else { 0 }, AcqRel);
+                let done_docs = metrics.docs.fetch_add(stats.completed_docs, AcqRel);
+                metrics.nquads.fetch_add(stats.completed_nquads, AcqRel);
+                let aborts = metrics.aborts.add(&stats.aborted);
+                let failed = metrics.failed.fetch_add(stats.failed_docs, AcqRel);
                 bar.inc(stats.completed_nquads);
                 bar.set_message(format!(
-                        "Txns:{} Docs:{} Aborts:{}",
-                        done_txns, done_docs, aborts));
+                        "Txns:{} Docs:{} Aborts:{} (aborted:{} resource-exhausted:{} unavailable:{} deadline-exceeded:{}) Failed:{}",
+                        done_txns, done_docs, aborts.total(), aborts.aborted, aborts.resource_exhausted,
+                        aborts.unavailable, aborts.deadline_exceeded, failed));
                 Ok(())
             }
         })
@@ -95,38 +529,82 @@ async fn main() -> Result<()> {
 struct ProcessChunkStats {
     completed_docs: u64,
     completed_nquads: u64,
-    aborted: u64,
+    aborted: AbortCounts,
+    failed_docs: u64,
+    committed: bool,
 }
 
 async fn process_chunk(
     client: &Client,
     upsert_patterns: &[Regex],
-    chunk: Vec<(usize, String)>
+    error_sink: &ErrorSink,
+    retry_policy: RetryPolicy,
+    metrics: &Metrics,
+    chunk: Vec<LineItem>
 ) -> Result<ProcessChunkStats> {
     let mut query = "{\n".into();
 
     let mut set_docs = vec![]; // merged to one mutation
+    let mut delete_docs = vec![]; // merged to one mutation
     let mut mutations = vec![]; // other mutations
 
-    let total_docs = chunk.len() as u64;
+    let mut completed_docs = 0;
     let mut total_nquads = 0;
-
-    for (index, json) in chunk {
-        let mut doc: Value = serde_json::from_str(&json)?;
+    let mut failed_docs = 0;
+    let mut skipped_indices = std::collections::HashSet::new();
+
+    for (index, json, parsed) in &chunk {
+        let (mut doc, nquads) = match parsed {
+            Ok((doc, nquads)) => (doc.clone(), *nquads),
+            Err(e) => {
+                error_sink.handle(*index, json, FailureReason::ParseError, e.clone()).await?;
+                skipped_indices.insert(*index);
+                failed_docs += 1;
+                continue;
+            }
+        };
         let mut offset = 0;
 
-        // Calculate anticipated nquad length
-        total_nquads += count_nquads(&doc);
+        // Roll back point, in case this document is rejected partway through recursion
+        let query_len = query.len();
+        let set_docs_len = set_docs.len();
+        let delete_docs_len = delete_docs.len();
+        let mutations_len = mutations.len();
 
-        process_doc(
+        let result = process_doc(
             upsert_patterns,
-            index,
+            *index,
             &mut offset,
             &mut query,
             &mut set_docs,
+            &mut delete_docs,
             &mut mutations,
-            &mut doc
-        )?;
+            &mut doc,
+            Op::Set
+        );
+
+        match result {
+            Ok(()) => {
+                completed_docs += 1;
+                total_nquads += nquads;
+            },
+            Err(e) => {
+                query.truncate(query_len);
+                set_docs.truncate(set_docs_len);
+                delete_docs.truncate(delete_docs_len);
+                mutations.truncate(mutations_len);
+
+                let reason = if e.downcast_ref::<InvalidOperation>().is_some() {
+                    FailureReason::InvalidOperation
+                } else {
+                    FailureReason::UnsupportedUid
+                };
+
+                error_sink.handle(*index, json, reason, e.to_string()).await?;
+                skipped_indices.insert(*index);
+                failed_docs += 1;
+            }
+        }
     }
 
     query.push('}');
@@ -142,42 +620,111 @@ async fn process_chunk(
         mutations.push(mutation);
     }
 
-    let mut aborted: u64 = 0;
+    // Create final mutation for delete-docs
+    if delete_docs.len() > 0 {
+        let mut mutation = Mutation::new();
+
+        mutation.set_delete_json(&delete_docs)?;
+
+        mutations.push(mutation);
+    }
+
+    let mut aborted = AbortCounts::default();
+
+    // Nothing to commit: every document was either set aside above or left no mutation
+    if mutations.is_empty() {
+        return Ok(ProcessChunkStats {
+            completed_docs,
+            completed_nquads: total_nquads,
+            aborted,
+            failed_docs,
+            committed: false,
+        });
+    }
+
+    // Retry transient failures with bounded exponential backoff, then give up
+    let mut attempt: u32 = 0;
 
-    // Retry aborted transactions or too many requests
     'retry: loop {
+        let started = Instant::now();
         let res = client.new_mutated_txn().upsert_and_commit_now(
             &query,
             mutations.clone()).await;
+        metrics.commit_latency.observe(started.elapsed());
 
         if let Err(e) = res {
             // Return if not a DgraphError
-            match e.downcast::<DgraphError>()? {
+            let dgraph_err = match e.downcast::<DgraphError>() {
+                Ok(e) => e,
+                Err(e) => {
+                    return handle_mutation_rejected(error_sink, &chunk, &skipped_indices, e).await
+                        .map(|failed| ProcessChunkStats {
+                            completed_docs: 0,
+                            completed_nquads: 0,
+                            aborted,
+                            failed_docs: failed_docs + failed,
+                            committed: false,
+                        });
+                }
+            };
+
+            match dgraph_err {
                 DgraphError::GrpcError(failure) => {
                     warn!("Grpc Error: Failure: {:?}", failure);
 
-                    match failure.downcast_ref::<ClientError>() {
-                        Some(ClientError::CannotDoRequest(ref status)) => {
-                            // Found the tonic Status
-                            match status.code() {
-                                // Maybe aborted transaction, or too many requests
-                                Code::Aborted | Code::ResourceExhausted => {
-                                    aborted += 1;
-                                    // Wait a (random) little bit, then retry
-                                    let msec = rand::thread_rng().gen_range(500..1500);
-                                    tokio::time::sleep(Duration::from_millis(msec)).await;
-                                    continue 'retry;
-                                },
-                                _ => ()
-                            }
-                        },
-                        _ => ()
+                    let code = match failure.downcast_ref::<ClientError>() {
+                        Some(ClientError::CannotDoRequest(ref status)) => Some(status.code()),
+                        _ => None,
+                    };
+
+                    // Maybe aborted transaction, too many requests, or a transient
+                    // unavailability - worth retrying, up to the configured budget
+                    let retry_counter = match code {
+                        Some(Code::Aborted) => Some(&mut aborted.aborted),
+                        Some(Code::ResourceExhausted) => Some(&mut aborted.resource_exhausted),
+                        Some(Code::Unavailable) => Some(&mut aborted.unavailable),
+                        Some(Code::DeadlineExceeded) => Some(&mut aborted.deadline_exceeded),
+                        _ => None,
+                    };
+
+                    if let Some(retry_counter) = retry_counter {
+                        attempt += 1;
+
+                        if attempt <= retry_policy.max_retries {
+                            *retry_counter += 1;
+                            tokio::time::sleep(retry_policy.backoff(attempt)).await;
+                            continue 'retry;
+                        }
+
+                        warn!("Exhausted {} retries; giving up on chunk", retry_policy.max_retries);
                     }
 
-                    // Otherwise... reconstruct the error and pass it up
-                    return Err(DgraphError::GrpcError(failure).into());
+                    // Permanent error, or retries exhausted: reconstruct the error and pass
+                    // it up, or set the whole chunk aside if we're tolerating failures
+                    let rejected = handle_mutation_rejected(
+                        error_sink, &chunk, &skipped_indices,
+                        DgraphError::GrpcError(failure).into()).await?;
+
+                    return Ok(ProcessChunkStats {
+                        completed_docs: 0,
+                        completed_nquads: 0,
+                        aborted,
+                        failed_docs: failed_docs + rejected,
+                        committed: false,
+                    });
                 },
-                other => return Err(other.into())
+                other => {
+                    let rejected = handle_mutation_rejected(
+                        error_sink, &chunk, &skipped_indices, other.into()).await?;
+
+                    return Ok(ProcessChunkStats {
+                        completed_docs: 0,
+                        completed_nquads: 0,
+                        aborted,
+                        failed_docs: failed_docs + rejected,
+                        committed: false,
+                    });
+                }
             }
         } else {
             break;
@@ -185,27 +732,93 @@ async fn process_chunk(
     }
 
     Ok(ProcessChunkStats {
-        completed_docs: total_docs,
+        completed_docs,
         completed_nquads: total_nquads,
-        aborted
+        aborted,
+        failed_docs,
+        committed: true,
     })
 }
 
+/// Sets aside every document in the chunk that hadn't already failed, under `--on-error=skip`;
+/// fatal under `--on-error=fail`. Returns how many documents were newly set aside.
+async fn handle_mutation_rejected(
+    error_sink: &ErrorSink,
+    chunk: &[LineItem],
+    already_skipped: &std::collections::HashSet<usize>,
+    err: anyhow::Error,
+) -> Result<u64> {
+    let message = err.to_string();
+    let mut rejected = 0;
+
+    for (index, json, _) in chunk {
+        if already_skipped.contains(index) {
+            continue;
+        }
+
+        error_sink.handle(*index, json, FailureReason::MutationRejected, message.clone()).await?;
+        rejected += 1;
+    }
+
+    Ok(rejected)
+}
+
+/// A document rejected because of its `_op` value or the delete op's upsert-key requirement,
+/// as opposed to the pre-existing structural bails (manual `uid`, non-object document) that
+/// report as `FailureReason::UnsupportedUid`
+#[derive(Debug)]
+struct InvalidOperation(String);
+
+impl std::fmt::Display for InvalidOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidOperation {}
+
+/// Whether a document sets or deletes data, chosen per-document via its `_op` field and
+/// inherited by any sub-documents that don't specify their own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Set,
+    Delete,
+}
+
+impl Op {
+    /// Reads and removes `_op` from the object, falling back to `parent` when absent
+    fn from_doc(obj: &mut serde_json::Map<String, Value>, index: usize, parent: Op) -> Result<Self> {
+        match obj.remove("_op") {
+            None => Ok(parent),
+            Some(Value::String(s)) if s == "set" => Ok(Op::Set),
+            Some(Value::String(s)) if s == "delete" => Ok(Op::Delete),
+            Some(other) => Err(InvalidOperation(format!(
+                "Unsupported _op value at {}: {} (expected \"set\" or \"delete\")", index, other)).into()),
+        }
+    }
+}
+
 /// Generates upsert queries for the doc and inserts references to them in `uid` fields
 ///
-/// Splits inner nodes into separate mutations, and creates conditional mutations for upsert keys
+/// Splits inner nodes into separate mutations, and creates conditional mutations for upsert
+/// keys. Under `Op::Delete` (see `_op`), matched nodes are retracted instead of written, keyed
+/// by the same upsert-pattern variable resolution, and become no-ops when nothing matches.
 fn process_doc(
     upsert_patterns: &[Regex],
     index: usize,
     offset: &mut u32,
     query: &mut String,
     set_docs: &mut Vec<Value>, // will be merged to one mutation
+    delete_docs: &mut Vec<Value>, // will be merged to one mutation
     mutations: &mut Vec<Mutation>,
-    doc: &mut Value
+    doc: &mut Value,
+    parent_op: Op
 ) -> Result<()> {
     use std::fmt::Write;
 
     if let Some(obj) = doc.as_object_mut() {
+        let op = Op::from_doc(obj, index, parent_op)?;
+
         let mut vars: Vec<(String, String, Value)> = vec![];
 
         // First, scan for any upsert keys and create variables for them
@@ -224,6 +837,11 @@ fn process_doc(
             }
         }
 
+        if op == Op::Delete && vars.is_empty() {
+            return Err(InvalidOperation(format!(
+                "Deleting requires at least one upsert key to match an existing node, at {}", index)).into());
+        }
+
         // Decide var name for the document itself (don't create extra query if only one var)
         let var_name = if vars.len() == 1 {
             vars[0].0.clone()
@@ -231,20 +849,36 @@ fn process_doc(
             format!("v_{}", index)
         };
 
-        // Add mutations for setting the upsert keys
+        // Add mutations for the upsert keys: conditionally set them on insert (only if not
+        // already present), or conditionally retract them on delete (only if present)
         for (var, key, value) in &vars {
             let mut mutation = Mutation::new();
 
-            trace!("Adding mutation for upsert key {} if var {} not set - set to {}",
-                key, var, value);
-
-            mutation.set_set_json(&json!(
-                [{
-                    "uid": format!("uid({})", var),
-                    key: value
-                }]
-            ))?;
-            mutation.set_cond(format!("@if(eq(len({}), 0))", var));
+            match op {
+                Op::Set => {
+                    trace!("Adding mutation for upsert key {} if var {} not set - set to {}",
+                        key, var, value);
+
+                    mutation.set_set_json(&json!(
+                        [{
+                            "uid": format!("uid({})", var),
+                            key: value
+                        }]
+                    ))?;
+                    mutation.set_cond(format!("@if(eq(len({}), 0))", var));
+                },
+                Op::Delete => {
+                    trace!("Adding mutation to delete key {} if var {} is set", key, var);
+
+                    mutation.set_delete_json(&json!(
+                        [{
+                            "uid": format!("uid({})", var),
+                            key: value
+                        }]
+                    ))?;
+                    mutation.set_cond(format!("@if(gt(len({}), 0))", var));
+                }
+            }
 
             mutations.push(mutation);
 
@@ -273,8 +907,10 @@ fn process_doc(
                         offset,
                         query,
                         set_docs,
+                        delete_docs,
                         mutations,
-                        value
+                        value,
+                        op
                     )?;
                 },
                 Value::Array(ref mut items) if items.iter().all(|i| is_node(&i)) => {
@@ -285,8 +921,10 @@ fn process_doc(
                             offset,
                             query,
                             set_docs,
+                            delete_docs,
                             mutations,
-                            item
+                            item,
+                            op
                         )?;
                     }
                 },
@@ -304,16 +942,21 @@ fn process_doc(
         // Set uid on my object to reference
         obj.insert("uid".into(), json!(this_ref.clone()));
 
-        // Add set-doc for setting all other properties, as long as there are still some to set
+        // Add set/delete-doc for the remaining properties, as long as there are still some left
         if obj.len() > 1 {
-            trace!("Adding set-doc for later mutation: {:?}", obj);
+            trace!("Adding {:?}-doc for later mutation: {:?}", op, obj);
 
             // Create plain reference object to leave in place
             let mut uid_obj = serde_json::Map::new();
 
             uid_obj.insert("uid".into(), json!(this_ref.clone()));
 
-            set_docs.push(json!(replace(obj, uid_obj)));
+            let doc = json!(replace(obj, uid_obj));
+
+            match op {
+                Op::Set => set_docs.push(doc),
+                Op::Delete => delete_docs.push(doc),
+            }
         }
     } else {
         bail!("Expected object at {}, but found other document: {}", index, doc);
@@ -345,7 +988,7 @@ fn is_node(value: &Value) -> bool {
 fn count_nquads(value: &Value) -> u64 {
     if is_node(value) {
         value.as_object().unwrap().iter()
-            .filter(|&(key, _)| key != "uid")
+            .filter(|&(key, _)| key != "uid" && key != "_op")
             .map(|(_, value)| count_nquads(value))
             .sum()
     } else {